@@ -1,15 +1,19 @@
 
+use std::borrow::Cow;
 use std::fmt;
 use std::error::Error;
 use http_types::{
+    Method,
     Request,
     Response,
     StatusCode,
+    Url,
 };
 
 // https://github.com/snapview/tungstenite-rs/blob/0c429cba9485e8f5efe9e51a8c088fcade93f35c/src/handshake/mod.rs#L115
 use sha1::{Digest, Sha1};
 use base64;
+use rand::Rng;
 /// Turns a Sec-WebSocket-Key into a Sec-WebSocket-Accept.
 pub fn convert_key(input: &[u8]) -> String {
     // ... field is constructed by concatenating /key/ ...
@@ -21,6 +25,14 @@ pub fn convert_key(input: &[u8]) -> String {
     base64::encode(&sha1.result())
 }
 
+/// Generates a random 16-byte nonce, base64-encoded, suitable for a
+/// Sec-WebSocket-Key header (RFC 6455 section 4.1).
+pub fn generate_key() -> String {
+    let mut nonce = [0u8; 16];
+    rand::thread_rng().fill(&mut nonce);
+    base64::encode(&nonce)
+}
+
 #[derive(Debug)]
 pub enum HandshakeError {
     MissingHeader(&'static str),
@@ -50,24 +62,267 @@ impl fmt::Display for HandshakeError {
 
 impl Error for HandshakeError {}
 
+/// A single offer from a `Sec-WebSocket-Extensions` header: an extension
+/// name plus its ordered `name=value` (or bare `name`) parameters, per the
+/// grammar in RFC 6455 section 9.1 / RFC 7692 section 5.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExtensionOffer<'a> {
+    pub name: &'a str,
+    pub params: Vec<(&'a str, Option<Cow<'a, str>>)>,
+}
+
+/// Splits `value` on `sep`, ignoring any `sep` found inside a quoted-string,
+/// so that e.g. a `;`-separated extension-param list doesn't get split on a
+/// `,` that happens to sit inside a quoted parameter value. A backslash
+/// inside a quoted-string escapes the following character (RFC 7230
+/// `quoted-pair`), so a `\"` doesn't end the quoted-string.
+fn split_unquoted(value: &str, sep: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut in_quotes = false;
+    let mut escaped = false;
+    let mut start = 0;
+    for (i, c) in value.char_indices() {
+        if escaped {
+            escaped = false;
+            continue
+        }
+        match c {
+            '\\' if in_quotes => escaped = true,
+            '"' => in_quotes = !in_quotes,
+            c if c == sep && !in_quotes => {
+                parts.push(&value[start..i]);
+                start = i + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    parts.push(&value[start..]);
+    parts
+}
+
+/// Strips a param value's surrounding quotes, if present, and un-escapes any
+/// `quoted-pair` (`\x`) sequences inside it.
+fn trim_quotes(value: &str) -> Cow<'_, str> {
+    let value = match value.strip_prefix('"').and_then(|value| value.strip_suffix('"')) {
+        Some(value) => value,
+        None => return Cow::Borrowed(value),
+    };
+
+    if !value.contains('\\') {
+        return Cow::Borrowed(value)
+    }
+
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            if let Some(escaped) = chars.next() {
+                unescaped.push(escaped);
+                continue
+            }
+        }
+        unescaped.push(c);
+    }
+    Cow::Owned(unescaped)
+}
+
+/// Parses a `Sec-WebSocket-Extensions` header value into its comma-separated
+/// offers, each with its semicolon-separated parameters.
+fn parse_extensions(value: &str) -> Vec<ExtensionOffer<'_>> {
+    split_unquoted(value, ',')
+        .into_iter()
+        .map(str::trim)
+        .filter(|ext_string| !ext_string.is_empty())
+        .map(|ext_string| {
+            let mut parts = split_unquoted(ext_string, ';').into_iter().map(str::trim);
+            let name = parts.next().unwrap_or("");
+            let params = parts
+                .filter(|param| !param.is_empty())
+                .map(|param| match param.split_once('=') {
+                    Some((key, value)) => (key.trim(), Some(trim_quotes(value.trim()))),
+                    None => (param, None),
+                })
+                .collect();
+
+            ExtensionOffer { name, params }
+        })
+        .collect()
+}
+
+/// Builds the client-side opening handshake request for `host`/`path`,
+/// returning it alongside the Sec-WebSocket-Key it sent so the response can
+/// later be checked with [`check_response_headers`].
+pub fn make_request(host: &str, path: &str) -> Result<(Request, String), <Url as std::str::FromStr>::Err> {
+    let url = Url::parse(&format!("ws://{}{}", host, path))?;
+    let key = generate_key();
+
+    let mut req = Request::new(Method::Get, url);
+    req.insert_header("Host", host);
+    req.insert_header("Connection", "Upgrade");
+    req.insert_header("Upgrade", "websocket");
+    req.insert_header("Sec-WebSocket-Version", "13");
+    req.insert_header("Sec-WebSocket-Key", &key);
+
+    Ok((req, key))
+}
+
+fn response_header<'a>(res: &'a Response, header: &'static str) -> Result<&'a str, HandshakeError> {
+    Ok(res.header(header)
+        .ok_or_else(|| HandshakeError::MissingHeader(header))?
+        .last()
+        .as_str()
+        )
+}
+
+/// Checks that `value` is a comma-separated, case-insensitive token list
+/// (RFC 6455 / RFC 7230 section 3.2.6) containing `token` somewhere in it.
+fn token_list_contains(header: &'static str, value: &str, token: &'static str) -> Result<(), HandshakeError> {
+    let present = value.split(',')
+        .map(str::trim)
+        .any(|t| t.eq_ignore_ascii_case(token));
+
+    if !present {
+        return Err(HandshakeError::InvalidHeaderValue {
+            header,
+            expected: Some(token),
+            found: value.to_string(),
+        })
+    }
+
+    Ok(())
+}
+
+/// Verifies a server's opening handshake response against the key this
+/// client sent in its request (see [`make_request`]).
+pub fn check_response_headers(response: &Response, sent_key: &str) -> Result<(), HandshakeError> {
+    if response.status() != StatusCode::SwitchingProtocols {
+        return Err(HandshakeError::InvalidHeaderValue {
+            header: "Status",
+            expected: Some("101"),
+            found: (response.status() as u16).to_string(),
+        })
+    }
+
+    let connection = response_header(response, "Connection")?;
+    token_list_contains("Connection", connection, "Upgrade")?;
+
+    let upgrade = response_header(response, "Upgrade")?;
+    token_list_contains("Upgrade", upgrade, "websocket")?;
+
+    let accept = response_header(response, "Sec-WebSocket-Accept")?;
+    let expected_accept = convert_key(sent_key.as_bytes());
+    if accept != expected_accept {
+        return Err(HandshakeError::InvalidHeaderValue {
+            header: "Sec-WebSocket-Accept",
+            expected: None,
+            found: accept.to_string(),
+        })
+    }
+
+    Ok(())
+}
+
 #[allow(dead_code)]
 pub struct HandshakeInfo<'a> {
     key: &'a str,
-    extensions: Vec<&'a str>,
+    extensions: Vec<ExtensionOffer<'a>>,
     protocols: Vec<&'a str>,
 }
 
 impl HandshakeInfo<'_> {
     pub fn make_response(&self) -> Response {
         let mut res = Response::new(StatusCode::SwitchingProtocols);
-        
+
         let accept = convert_key(self.key.as_bytes());
         res.insert_header("Upgrade", "websocket");
         res.insert_header("Connection", "Upgrade");
         res.insert_header("Sec-WebSocket-Accept", accept);
-        
+
         res
     }
+
+    /// Like [`make_response`](Self::make_response), but also negotiates a
+    /// subprotocol and extensions: if `protocol` is `Some`, it is echoed back
+    /// via `Sec-WebSocket-Protocol` (RFC 6455 section 4.2.2 requires the
+    /// server select at most one of the client's offered protocols), and if
+    /// `extensions` is `Some` it is sent as `Sec-WebSocket-Extensions`
+    /// (see [`negotiate_permessage_deflate`](Self::negotiate_permessage_deflate)).
+    pub fn make_response_with(&self, protocol: Option<&str>, extensions: Option<&str>) -> Response {
+        let mut res = self.make_response();
+
+        if let Some(protocol) = protocol {
+            res.insert_header("Sec-WebSocket-Protocol", protocol);
+        }
+
+        if let Some(extensions) = extensions {
+            res.insert_header("Sec-WebSocket-Extensions", extensions);
+        }
+
+        res
+    }
+
+    /// Picks the first client-offered protocol (see `protocols`) that also
+    /// appears in `supported`, for use with [`make_response_with`](Self::make_response_with).
+    ///
+    /// Sec-WebSocket-Protocol values are opaque, server-registered
+    /// identifiers rather than an RFC 7230 token list, so unlike `Connection`
+    /// or `Upgrade` this match is case-sensitive (e.g. `graphql-ws` and
+    /// `GraphQL-WS` are conventionally distinct protocols).
+    pub fn select_protocol(&self, supported: &[&str]) -> Option<&str> {
+        self.protocols.iter()
+            .find(|offered| supported.contains(offered))
+            .copied()
+    }
+
+    /// Looks for a `permessage-deflate` offer (RFC 7692) among `extensions`
+    /// and, if present, builds the `Sec-WebSocket-Extensions` response value
+    /// accepting it, echoing any `server_no_context_takeover` /
+    /// `client_no_context_takeover` / `server_max_window_bits` /
+    /// `client_max_window_bits` parameters the client offered. The two
+    /// `*_max_window_bits` parameters are echoed only when they carry a
+    /// value; a bare `client_max_window_bits` offer is left out.
+    pub fn negotiate_permessage_deflate(&self) -> Option<String> {
+        let offer = self.extensions.iter()
+            .find(|ext| ext.name.eq_ignore_ascii_case("permessage-deflate"))?;
+
+        let mut accepted = String::from("permessage-deflate");
+        for (key, value) in &offer.params {
+            match key.to_ascii_lowercase().as_str() {
+                "server_no_context_takeover" | "client_no_context_takeover" => {
+                    accepted.push_str("; ");
+                    accepted.push_str(key);
+                }
+                "server_max_window_bits" | "client_max_window_bits" => {
+                    if let Some(value) = value {
+                        accepted.push_str("; ");
+                        accepted.push_str(key);
+                        accepted.push('=');
+                        accepted.push_str(value);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Some(accepted)
+    }
+}
+
+/// Checks that `key` is the base64 encoding of a 16-byte value, as required
+/// of a Sec-WebSocket-Key by RFC 6455 section 4.1.
+fn validate_key(key: &str) -> Result<(), HandshakeError> {
+    let invalid = || HandshakeError::InvalidHeaderValue {
+        header: "Sec-WebSocket-Key",
+        expected: None,
+        found: key.to_string(),
+    };
+
+    let decoded = base64::decode(key).map_err(|_| invalid())?;
+    if decoded.len() != 16 {
+        return Err(invalid())
+    }
+
+    Ok(())
 }
 
 fn assert_header<'a>(req: &'a Request, header: &'static str, expected: &'static str) -> Result<(), HandshakeError> {
@@ -88,8 +343,20 @@ fn assert_header<'a>(req: &'a Request, header: &'static str, expected: &'static
 }
 
 pub fn check_request_headers(request: &Request) -> Result<HandshakeInfo, HandshakeError> {
-    assert_header(request, "Connection", "Upgrade")?;
-    assert_header(request, "Upgrade", "websocket")?;
+    let connection = request.header("Connection")
+        .ok_or_else(|| HandshakeError::MissingHeader("Connection"))?
+        .last()
+        .as_str()
+        ;
+    token_list_contains("Connection", connection, "Upgrade")?;
+
+    let upgrade = request.header("Upgrade")
+        .ok_or_else(|| HandshakeError::MissingHeader("Upgrade"))?
+        .last()
+        .as_str()
+        ;
+    token_list_contains("Upgrade", upgrade, "websocket")?;
+
     assert_header(request, "Sec-WebSocket-Version", "13")?;
     
     let key = request
@@ -98,7 +365,8 @@ pub fn check_request_headers(request: &Request) -> Result<HandshakeInfo, Handsha
         .last()
         .as_str()
         ;
-    
+    validate_key(key)?;
+
     // grammar for headers
     // https://tools.ietf.org/html/rfc6455#section-4.3
     // https://tools.ietf.org/html/rfc6455#section-9.1
@@ -108,18 +376,8 @@ pub fn check_request_headers(request: &Request) -> Result<HandshakeInfo, Handsha
         // don't use .iter since it explicitly doesn't guarantee ordering
         let mut i = 0;
         while let Some(value) = values.get(i) {
-            let value = value.as_str();
-            for ext_string in value.split(",") {
-                // skip extensions with parameters for now
-                if ext_string.contains(";") {
-                    continue
-                }
-                
-                let ext_string = ext_string.trim();
-                
-                extensions.push(ext_string);
-            }
-            
+            extensions.extend(parse_extensions(value.as_str()));
+
             i += 1;
         }
     }
@@ -144,3 +402,151 @@ pub fn check_request_headers(request: &Request) -> Result<HandshakeInfo, Handsha
         protocols,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_quoted_param_value_with_escaped_quote() {
+        let offers = parse_extensions(r#"permessage-deflate; x="a\"; bar=1""#);
+        assert_eq!(offers.len(), 1);
+        assert_eq!(offers[0].name, "permessage-deflate");
+        assert_eq!(offers[0].params, vec![
+            ("x", Some(Cow::Borrowed("a\"; bar=1"))),
+        ]);
+    }
+
+    #[test]
+    fn negotiates_permessage_deflate_round_trip() {
+        let extensions = parse_extensions(
+            "permessage-deflate; client_no_context_takeover; server_max_window_bits=10"
+        );
+        let info = HandshakeInfo {
+            key: "dGhlIHNhbXBsZSBub25jZQ==",
+            extensions,
+            protocols: Vec::new(),
+        };
+
+        assert_eq!(
+            info.negotiate_permessage_deflate().as_deref(),
+            Some("permessage-deflate; client_no_context_takeover; server_max_window_bits=10"),
+        );
+    }
+
+    #[test]
+    fn negotiates_permessage_deflate_params_case_insensitively() {
+        let extensions = parse_extensions("permessage-deflate; Server_No_Context_Takeover");
+        let info = HandshakeInfo {
+            key: "dGhlIHNhbXBsZSBub25jZQ==",
+            extensions,
+            protocols: Vec::new(),
+        };
+
+        assert_eq!(
+            info.negotiate_permessage_deflate().as_deref(),
+            Some("permessage-deflate; Server_No_Context_Takeover"),
+        );
+    }
+
+    #[test]
+    fn validate_key_accepts_a_16_byte_key() {
+        assert!(validate_key("dGhlIHNhbXBsZSBub25jZQ==").is_ok());
+    }
+
+    #[test]
+    fn validate_key_rejects_wrong_length() {
+        assert!(validate_key(&base64::encode(b"too short")).is_err());
+    }
+
+    #[test]
+    fn validate_key_rejects_non_base64() {
+        assert!(validate_key("not valid base64!!").is_err());
+    }
+
+    fn valid_response(sent_key: &str) -> Response {
+        let mut res = Response::new(StatusCode::SwitchingProtocols);
+        res.insert_header("Upgrade", "websocket");
+        res.insert_header("Connection", "Upgrade");
+        res.insert_header("Sec-WebSocket-Accept", convert_key(sent_key.as_bytes()));
+        res
+    }
+
+    #[test]
+    fn make_request_sets_required_headers() {
+        let (req, key) = make_request("example.com", "/chat").unwrap();
+        assert_eq!(req.header("Host").unwrap().last().as_str(), "example.com");
+        assert_eq!(req.header("Connection").unwrap().last().as_str(), "Upgrade");
+        assert_eq!(req.header("Upgrade").unwrap().last().as_str(), "websocket");
+        assert_eq!(req.header("Sec-WebSocket-Version").unwrap().last().as_str(), "13");
+        assert_eq!(req.header("Sec-WebSocket-Key").unwrap().last().as_str(), key);
+    }
+
+    #[test]
+    fn check_response_headers_accepts_a_valid_response() {
+        let sent_key = "dGhlIHNhbXBsZSBub25jZQ==";
+        let res = valid_response(sent_key);
+        assert!(check_response_headers(&res, sent_key).is_ok());
+    }
+
+    #[test]
+    fn check_response_headers_rejects_wrong_status() {
+        let sent_key = "dGhlIHNhbXBsZSBub25jZQ==";
+        let mut res = valid_response(sent_key);
+        res.set_status(StatusCode::Ok);
+        assert!(check_response_headers(&res, sent_key).is_err());
+    }
+
+    #[test]
+    fn check_response_headers_rejects_bad_accept() {
+        let sent_key = "dGhlIHNhbXBsZSBub25jZQ==";
+        let mut res = valid_response(sent_key);
+        res.insert_header("Sec-WebSocket-Accept", "not-the-right-value");
+        assert!(check_response_headers(&res, sent_key).is_err());
+    }
+
+    #[test]
+    fn check_response_headers_rejects_connection_substring_match() {
+        let sent_key = "dGhlIHNhbXBsZSBub25jZQ==";
+        let mut res = valid_response(sent_key);
+        res.insert_header("Connection", "notupgradeable");
+        assert!(check_response_headers(&res, sent_key).is_err());
+    }
+
+    #[test]
+    fn token_list_contains_matches_reordered_mixed_case_multi_token_lists() {
+        assert!(token_list_contains("Connection", "keep-alive, Upgrade", "Upgrade").is_ok());
+        assert!(token_list_contains("Upgrade", "WebSocket", "websocket").is_ok());
+    }
+
+    #[test]
+    fn token_list_contains_rejects_substring_only_match() {
+        assert!(token_list_contains("Connection", "upgradeable", "Upgrade").is_err());
+    }
+
+    #[test]
+    fn select_protocol_picks_first_offered_protocol_present_in_supported() {
+        let info = HandshakeInfo {
+            key: "dGhlIHNhbXBsZSBub25jZQ==",
+            extensions: Vec::new(),
+            protocols: vec!["graphql-ws", "mqtt"],
+        };
+
+        assert_eq!(info.select_protocol(&["mqtt"]), Some("mqtt"));
+        assert_eq!(info.select_protocol(&["graphql-ws", "mqtt"]), Some("graphql-ws"));
+        assert_eq!(info.select_protocol(&["GraphQL-WS"]), None);
+    }
+
+    #[test]
+    fn make_response_with_echoes_the_selected_protocol() {
+        let info = HandshakeInfo {
+            key: "dGhlIHNhbXBsZSBub25jZQ==",
+            extensions: Vec::new(),
+            protocols: vec!["mqtt"],
+        };
+
+        let protocol = info.select_protocol(&["mqtt"]);
+        let res = info.make_response_with(protocol, None);
+        assert_eq!(res.header("Sec-WebSocket-Protocol").unwrap().last().as_str(), "mqtt");
+    }
+}